@@ -1,20 +1,165 @@
-use tokio::{net::TcpListener, io::{AsyncReadExt, AsyncWriteExt}};
+use tokio::{net::TcpListener, io::{AsyncReadExt, AsyncWriteExt, AsyncBufReadExt, BufReader}};
 use serde::{Serialize, Deserialize};
 use serde_json::{json, Value};
 use std::error::Error;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio::sync::mpsc;
+use tokio::net::TcpStream;
+use sha1::{Sha1, Digest};
+use base64::Engine;
+
+/// Magic GUID from RFC 6455 appended to the client key during the handshake.
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How long an HTTP client may stall, mid-request or idle between keep-alive
+/// requests, before it is sent a 408 and the connection is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest request body / WebSocket frame accepted before the peer is rejected,
+/// unless overridden by `MCP_MAX_BODY_SIZE`. Bounds the memory one client can
+/// make the server buffer.
+const DEFAULT_MAX_BODY_SIZE: usize = 1 << 20; // 1 MiB
+
+const RESPONSE_408: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nContent-Type: text/plain\r\nContent-Length: 15\r\nConnection: close\r\n\r\nRequest timeout";
+const RESPONSE_405: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\nContent-Type: text/plain\r\nContent-Length: 17\r\nConnection: close\r\n\r\nUse POST requests";
+const RESPONSE_413: &[u8] = b"HTTP/1.1 413 Payload Too Large\r\nContent-Type: text/plain\r\nContent-Length: 17\r\nConnection: close\r\n\r\nPayload too large";
+
+/// Return the index of the first occurrence of `needle` within `haystack`.
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// A decoded WebSocket frame, narrowed to the cases the server acts on.
+enum WsFrame {
+    Text(String),
+    Close,
+    Other,
+}
+
+/// Compute the `Sec-WebSocket-Accept` value for a client's key per RFC 6455.
+fn websocket_accept(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WS_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+/// Read from the socket until `buffer` holds at least `n` bytes; false on EOF/error.
+async fn ws_fill(stream: &mut TcpStream, buffer: &mut Vec<u8>, n: usize) -> bool {
+    let mut scratch = [0u8; 4096];
+    while buffer.len() < n {
+        match stream.read(&mut scratch).await {
+            Ok(0) => return false,
+            Ok(k) => buffer.extend_from_slice(&scratch[..k]),
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+/// Decode one client frame, unmasking its payload. `buffer` carries bytes that
+/// arrived ahead of the frame boundary between calls.
+async fn ws_read(stream: &mut TcpStream, buffer: &mut Vec<u8>, max_frame: usize) -> WsFrame {
+    if !ws_fill(stream, buffer, 2).await {
+        return WsFrame::Close;
+    }
+    let opcode = buffer[0] & 0x0f;
+    let masked = buffer[1] & 0x80 != 0;
+    let mut len = (buffer[1] & 0x7f) as usize;
+    let mut offset = 2;
+
+    if len == 126 {
+        if !ws_fill(stream, buffer, offset + 2).await {
+            return WsFrame::Close;
+        }
+        len = u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) as usize;
+        offset += 2;
+    } else if len == 127 {
+        if !ws_fill(stream, buffer, offset + 8).await {
+            return WsFrame::Close;
+        }
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buffer[offset..offset + 8]);
+        len = u64::from_be_bytes(bytes) as usize;
+        offset += 8;
+    }
+
+    // Refuse a frame larger than the cap rather than buffering its payload.
+    if len > max_frame {
+        return WsFrame::Close;
+    }
+
+    let mask = if masked {
+        if !ws_fill(stream, buffer, offset + 4).await {
+            return WsFrame::Close;
+        }
+        let m = [buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]];
+        offset += 4;
+        Some(m)
+    } else {
+        None
+    };
+
+    if !ws_fill(stream, buffer, offset + len).await {
+        return WsFrame::Close;
+    }
+    let mut payload = buffer[offset..offset + len].to_vec();
+    if let Some(m) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= m[i % 4];
+        }
+    }
+    buffer.drain(..offset + len);
+
+    match opcode {
+        0x1 => String::from_utf8(payload).map(WsFrame::Text).unwrap_or(WsFrame::Other),
+        0x8 => WsFrame::Close,
+        _ => WsFrame::Other,
+    }
+}
+
+/// Send a single unmasked text frame to the client; false if the write failed.
+async fn ws_write_text(stream: &mut TcpStream, text: &str) -> bool {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81u8]; // FIN + text opcode
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await.is_ok()
+}
+
+fn default_jsonrpc() -> String {
+    "2.0".to_string()
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct MCPRequest {
-    id: String,
+    #[serde(default = "default_jsonrpc")]
+    jsonrpc: String,
+    /// Absent for notifications, which are processed without a reply.
+    #[serde(default)]
+    id: Option<Value>,
     method: String,
+    #[serde(default)]
     params: Value,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct MCPResponse {
-    id: String,
+    #[serde(default = "default_jsonrpc")]
+    jsonrpc: String,
+    id: Value,
     result: Value,
     error: Option<MCPError>,
 }
@@ -26,125 +171,576 @@ struct MCPError {
 }
 
 struct MCPServer {
-    models: HashMap<String, ModelHandler>,
+    models: HashMap<String, Arc<dyn Model>>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    tokens: Arc<HashSet<String>>,
+    /// Maximum number of live sessions before `session.create` is refused.
+    max_sessions: usize,
+    /// Largest request body / WebSocket frame accepted, in bytes.
+    max_body_size: usize,
+    /// Sessions idle longer than this are swept by the background reaper.
+    idle_ttl: Duration,
+}
+
+/// The shared state a request needs to dispatch, bundled so it can be threaded
+/// through the transports and `process_request` as one argument. Cloning is cheap:
+/// `models` is a small map of `Arc` handles, and every other field is an `Arc`
+/// or a `Copy` scalar.
+#[derive(Clone)]
+struct Dispatch {
+    models: HashMap<String, Arc<dyn Model>>,
     sessions: Arc<Mutex<HashMap<String, Session>>>,
+    tokens: Arc<HashSet<String>>,
+    max_sessions: usize,
+    max_body_size: usize,
 }
 
 struct Session {
     model: String,
     context: Vec<String>,
+    /// Token identity that created the session; only it may use or close it.
+    owner: String,
+    /// When the session was created; reported as its age in `session.list`.
+    created: Instant,
+    /// Last time the session was created or generated against; drives expiry.
+    last_used: Instant,
 }
 
-type ModelHandler = fn(prompt: &str, context: &[String]) -> Result<String, Box<dyn Error + Send + Sync>>;
+/// A registered model backend. Implementors may hold their own state (API keys,
+/// connection pools) and `.await` on network calls. Chunks are yielded through
+/// `tx` as they are produced — the WebSocket transport forwards each one to the
+/// client immediately — and the fully aggregated reply is returned at the end.
+#[async_trait::async_trait]
+trait Model: Send + Sync {
+    async fn generate(
+        &self,
+        prompt: &str,
+        context: &[String],
+        tx: mpsc::UnboundedSender<String>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>>;
+}
 
 impl MCPServer {
     fn new() -> Self {
         MCPServer {
             models: HashMap::new(),
             sessions: Arc::new(Mutex::new(HashMap::new())),
+            tokens: Arc::new(HashSet::new()),
+            max_sessions: 1000,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            idle_ttl: Duration::from_secs(300),
+        }
+    }
+
+    /// Spawn a background task that periodically evicts sessions idle past `ttl`.
+    fn spawn_reaper(sessions: Arc<Mutex<HashMap<String, Session>>>, ttl: Duration) {
+        let interval = (ttl / 2).max(Duration::from_secs(1));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let mut sessions_guard = sessions.lock().unwrap();
+                sessions_guard.retain(|_, session| session.last_used.elapsed() < ttl);
+            }
+        });
+    }
+
+    /// Resolve and validate the caller's token, returning its identity.
+    ///
+    /// The token comes from the transport (HTTP `Authorization: Bearer`) or, for
+    /// non-HTTP transports, from `params.token`. When no tokens are configured the
+    /// server runs open and the caller is treated as anonymous.
+    fn authenticate(tokens: &HashSet<String>, auth: &Option<String>, params: &Value) -> Result<String, ()> {
+        let token = auth.clone().or_else(|| {
+            params.get("token").and_then(|v| v.as_str()).map(String::from)
+        });
+
+        if tokens.is_empty() {
+            return Ok(token.unwrap_or_else(|| "anonymous".to_string()));
+        }
+
+        match token {
+            Some(t) if tokens.contains(&t) => Ok(t),
+            _ => Err(()),
         }
     }
 
-    fn register_model(&mut self, name: &str, handler: ModelHandler) {
-        self.models.insert(name.to_string(), handler);
+    fn register_model(&mut self, name: &str, model: Arc<dyn Model>) {
+        self.models.insert(name.to_string(), model);
+    }
+
+    /// Snapshot the state each connection needs into a cheaply cloned bundle.
+    fn dispatch(&self) -> Dispatch {
+        Dispatch {
+            models: self.models.clone(),
+            sessions: self.sessions.clone(),
+            tokens: self.tokens.clone(),
+            max_sessions: self.max_sessions,
+            max_body_size: self.max_body_size,
+        }
+    }
+
+    async fn handle_client(mut stream: tokio::net::TcpStream, dispatch: Dispatch) {
+        let mut buffer: Vec<u8> = Vec::with_capacity(8192);
+        let mut scratch = vec![0u8; 8192];
+
+        // Serve sequential requests on the same socket until the peer closes or stalls.
+        loop {
+            // Accumulate bytes until the header block terminator is present.
+            let header_end = loop {
+                if let Some(pos) = find_subsequence(&buffer, b"\r\n\r\n") {
+                    break pos;
+                }
+                match timeout(IDLE_TIMEOUT, stream.read(&mut scratch)).await {
+                    Ok(Ok(0)) => return, // peer closed the connection
+                    Ok(Ok(n)) => buffer.extend_from_slice(&scratch[..n]),
+                    Ok(Err(e)) => {
+                        eprintln!("Failed to read from socket: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        let _ = stream.write_all(RESPONSE_408).await;
+                        return;
+                    }
+                }
+            };
+
+            let header_text = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+            let mut lines = header_text.split("\r\n");
+            let request_line = lines.next().unwrap_or("").to_string();
+
+            // Pull out the headers we care about: body length, WebSocket upgrade, and bearer token.
+            let mut content_length = 0usize;
+            let mut upgrade_websocket = false;
+            let mut websocket_key = String::new();
+            let mut auth: Option<String> = None;
+            for line in lines {
+                if let Some((name, value)) = line.split_once(':') {
+                    let name = name.trim();
+                    let value = value.trim();
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.parse().unwrap_or(0);
+                    } else if name.eq_ignore_ascii_case("upgrade") && value.eq_ignore_ascii_case("websocket") {
+                        upgrade_websocket = true;
+                    } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                        websocket_key = value.to_string();
+                    } else if name.eq_ignore_ascii_case("authorization") {
+                        auth = value.strip_prefix("Bearer ").map(|t| t.trim().to_string());
+                    }
+                }
+            }
+
+            // A WebSocket upgrade takes over the connection for streaming.
+            if upgrade_websocket && request_line.starts_with("GET") {
+                buffer.drain(..header_end + 4);
+                Self::handle_websocket(stream, websocket_key, buffer, dispatch, auth).await;
+                return;
+            }
+
+            if !request_line.starts_with("POST") {
+                let _ = stream.write_all(RESPONSE_405).await;
+                return;
+            }
+
+            // Reject unauthenticated HTTP callers with a 401 status before dispatch.
+            // Over HTTP the credential is the `Authorization: Bearer` header.
+            if !dispatch.tokens.is_empty() && !auth.as_ref().map(|t| dispatch.tokens.contains(t)).unwrap_or(false) {
+                let error_json = serde_json::to_string(
+                    &Self::error_response(Value::Null, -32001, "Unauthorized: invalid or missing token")
+                ).unwrap();
+                let http_response = format!(
+                    "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    error_json.len(),
+                    error_json
+                );
+                let _ = stream.write_all(http_response.as_bytes()).await;
+                return;
+            }
+
+            // Refuse an oversized body before buffering it, so a large
+            // Content-Length can't make the server allocate unboundedly.
+            if content_length > dispatch.max_body_size {
+                let _ = stream.write_all(RESPONSE_413).await;
+                return;
+            }
+
+            // Read the body in full, growing the buffer as segments arrive.
+            let body_start = header_end + 4;
+            while buffer.len() < body_start + content_length {
+                match timeout(IDLE_TIMEOUT, stream.read(&mut scratch)).await {
+                    Ok(Ok(0)) => return, // peer closed mid-body
+                    Ok(Ok(n)) => buffer.extend_from_slice(&scratch[..n]),
+                    Ok(Err(e)) => {
+                        eprintln!("Failed to read from socket: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        let _ = stream.write_all(RESPONSE_408).await;
+                        return;
+                    }
+                }
+            }
+
+            let body = &buffer[body_start..body_start + content_length];
+            let body_str = String::from_utf8_lossy(body).to_string();
+
+            // A batch of only notifications yields an empty body.
+            let response_json = Self::handle_message(&dispatch, &body_str, &auth)
+                .await
+                .unwrap_or_default();
+
+            let http_response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: keep-alive\r\n\r\n{}",
+                response_json.len(),
+                response_json
+            );
+
+            if stream.write_all(http_response.as_bytes()).await.is_err() {
+                return;
+            }
+
+            // Drop the consumed request so any pipelined bytes stay for the next loop.
+            buffer.drain(..body_start + content_length);
+        }
     }
 
-    async fn handle_client(mut stream: tokio::net::TcpStream, models: HashMap<String, ModelHandler>, sessions: Arc<Mutex<HashMap<String, Session>>>) {
-        let mut buffer = vec![0; 8192];
-        
-        match stream.read(&mut buffer).await {
-            Ok(n) => {
-                if n == 0 {
+    /// Serve JSON-RPC over stdin/stdout using LSP-style `Content-Length` framing.
+    ///
+    /// Each message is a run of `Header: value\r\n` lines terminated by a blank
+    /// `\r\n`, followed by exactly `Content-Length` bytes of JSON body. Replies are
+    /// written back with the same framing. This lets the server be launched as a
+    /// child process instead of being reached over the socket.
+    async fn run_stdio(dispatch: Dispatch) {
+        let mut reader = BufReader::new(tokio::io::stdin());
+        let mut stdout = tokio::io::stdout();
+
+        loop {
+            // Read headers until the blank line, capturing Content-Length.
+            let mut content_length: Option<usize> = None;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match reader.read_line(&mut line).await {
+                    Ok(0) => return, // EOF
+                    Ok(_) => {},
+                    Err(e) => {
+                        eprintln!("Failed to read from stdin: {}", e);
+                        return;
+                    }
+                }
+
+                let header = line.trim_end_matches('\n').trim_end_matches('\r');
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length:") {
+                    content_length = value.trim().parse().ok();
+                }
+            }
+
+            let length = match content_length {
+                Some(len) => len,
+                None => continue, // Skip a framing we can't size.
+            };
+
+            // Drop an oversized body without allocating it, then await the next frame.
+            if length > dispatch.max_body_size {
+                let mut remaining = length;
+                let mut scratch = [0u8; 8192];
+                while remaining > 0 {
+                    let take = remaining.min(scratch.len());
+                    if reader.read_exact(&mut scratch[..take]).await.is_err() {
+                        return;
+                    }
+                    remaining -= take;
+                }
+                continue;
+            }
+
+            let mut body = vec![0u8; length];
+            if let Err(e) = reader.read_exact(&mut body).await {
+                eprintln!("Failed to read message body from stdin: {}", e);
+                return;
+            }
+
+            let body_str = String::from_utf8_lossy(&body).to_string();
+
+            // Non-HTTP transports authenticate via params.token, so no header auth here.
+            if let Some(response_json) = Self::handle_message(&dispatch, &body_str, &None).await {
+                let framed = format!("Content-Length: {}\r\n\r\n{}", response_json.len(), response_json);
+                if stdout.write_all(framed.as_bytes()).await.is_err() || stdout.flush().await.is_err() {
                     return;
                 }
-                
-                let request_data = &buffer[0..n];
-                let request_str = String::from_utf8_lossy(request_data);
-                
-                // Very basic HTTP parsing
-                if request_str.starts_with("POST") {
-                    // Find the JSON body after the double newline
-                    if let Some(body_start) = request_str.find("\r\n\r\n") {
-                        let body = &request_str[body_start + 4..];
-                        
-                        // Parse the JSON request
-                        match serde_json::from_str::<MCPRequest>(body) {
-                            Ok(request) => {
-                                let response = Self::process_request(request, &models, &sessions).await;
-                                let response_json = serde_json::to_string(&response).unwrap();
-                                
-                                // Send HTTP response
-                                let http_response = format!(
-                                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-                                    response_json.len(),
-                                    response_json
-                                );
-                                
-                                let _ = stream.write_all(http_response.as_bytes()).await;
-                            },
-                            Err(e) => {
-                                let error_response = json!({
-                                    "id": "error",
-                                    "error": {
-                                        "code": -32700,
-                                        "message": format!("Parse error: {}", e)
-                                    },
-                                    "result": null
-                                });
-                                
-                                let error_json = serde_json::to_string(&error_response).unwrap();
-                                let http_response = format!(
-                                    "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-                                    error_json.len(),
-                                    error_json
-                                );
-                                
-                                let _ = stream.write_all(http_response.as_bytes()).await;
-                            }
+            }
+        }
+    }
+
+    /// Complete the WebSocket handshake and serve framed JSON-RPC on the upgraded
+    /// connection, streaming `session.generate` output chunk by chunk.
+    async fn handle_websocket(
+        mut stream: TcpStream,
+        key: String,
+        mut buffer: Vec<u8>,
+        dispatch: Dispatch,
+        auth: Option<String>,
+    ) {
+        let handshake = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            websocket_accept(&key)
+        );
+        if stream.write_all(handshake.as_bytes()).await.is_err() {
+            return;
+        }
+
+        loop {
+            match ws_read(&mut stream, &mut buffer, dispatch.max_body_size).await {
+                WsFrame::Text(text) => {
+                    let request = match serde_json::from_str::<MCPRequest>(&text) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            let response = Self::error_response(Value::Null, -32700, &format!("Parse error: {}", e));
+                            let _ = ws_write_text(&mut stream, &serde_json::to_string(&response).unwrap()).await;
+                            continue;
+                        }
+                    };
+
+                    if request.id.is_none() {
+                        // Notifications are processed without a reply, streamed or otherwise.
+                        let _ = Self::process_request(request, &dispatch, &auth).await;
+                    } else if request.method == "session.generate" {
+                        if !Self::ws_stream_generate(&mut stream, request, &dispatch, &auth).await {
+                            return;
                         }
                     } else {
-                        // No body found
-                        let error_response = "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: 19\r\n\r\nMissing request body";
-                        let _ = stream.write_all(error_response.as_bytes()).await;
+                        let response = Self::process_request(request, &dispatch, &auth).await;
+                        if !ws_write_text(&mut stream, &serde_json::to_string(&response).unwrap()).await {
+                            return;
+                        }
                     }
-                } else {
-                    // Not a POST request
-                    let error_response = "HTTP/1.1 405 Method Not Allowed\r\nContent-Type: text/plain\r\nContent-Length: 18\r\n\r\nUse POST requests";
-                    let _ = stream.write_all(error_response.as_bytes()).await;
                 }
-            },
+                WsFrame::Close => return,
+                WsFrame::Other => continue,
+            }
+        }
+    }
+
+    /// Run a `session.generate` over a WebSocket, pushing one frame per chunk and a
+    /// terminal "done" frame with the aggregated response and turn number. Returns
+    /// false when the connection can no longer be written to.
+    async fn ws_stream_generate(
+        stream: &mut TcpStream,
+        request: MCPRequest,
+        dispatch: &Dispatch,
+        auth: &Option<String>,
+    ) -> bool {
+        let id = request.id.clone().unwrap_or(Value::Null);
+
+        let caller = match Self::authenticate(&dispatch.tokens, auth, &request.params) {
+            Ok(caller) => caller,
+            Err(()) => {
+                let response = Self::error_response(id, -32001, "Unauthorized: invalid or missing token");
+                return ws_write_text(stream, &serde_json::to_string(&response).unwrap()).await;
+            }
+        };
+
+        let session_id = match request.params.get("session_id") {
+            Some(Value::String(sid)) => sid.clone(),
+            _ => {
+                let response = Self::error_response(id, -32602, "Invalid params: missing session_id");
+                return ws_write_text(stream, &serde_json::to_string(&response).unwrap()).await;
+            }
+        };
+        let prompt = match request.params.get("prompt") {
+            Some(Value::String(p)) => p.clone(),
+            _ => {
+                let response = Self::error_response(id, -32602, "Invalid params: missing prompt");
+                return ws_write_text(stream, &serde_json::to_string(&response).unwrap()).await;
+            }
+        };
+
+        // Resolve owned values (or an owned error) under the lock and release it
+        // before any await — holding a guard reference across `.await` would make
+        // the enclosing future non-Send and break `tokio::spawn`.
+        let snapshot = {
+            let sessions_guard = dispatch.sessions.lock().unwrap();
+            match sessions_guard.get(&session_id) {
+                None => Err(Self::error_response(id.clone(), -32602, &format!("Session not found: {}", session_id))),
+                Some(session) if session.owner != caller => {
+                    Err(Self::error_response(id.clone(), -32001, "Unauthorized: session owned by another token"))
+                }
+                Some(session) => match dispatch.models.get(&session.model) {
+                    Some(model) => Ok((model.clone(), session.context.clone(), session.context.len() / 2 + 1)),
+                    None => Err(Self::error_response(id.clone(), -32603, "Internal error: model handler not found")),
+                },
+            }
+        };
+        let (model, context, turn) = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(response) => return ws_write_text(stream, &serde_json::to_string(&response).unwrap()).await,
+        };
+
+        // Run generation concurrently with frame forwarding so each chunk reaches
+        // the client as it is produced, not after the whole reply is aggregated.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let gen_prompt = prompt.clone();
+        let mut generate = Box::pin(async move { model.generate(&gen_prompt, &context, tx).await });
+
+        let mut rx_open = true;
+        let result = loop {
+            tokio::select! {
+                // Drain chunks first so the channel doesn't buffer ahead of the client.
+                biased;
+                // Once every sender has dropped, recv() resolves immediately forever;
+                // disable the arm so the select only waits on the generate future.
+                chunk = rx.recv(), if rx_open => {
+                    if let Some(chunk) = chunk {
+                        let frame = json!({ "jsonrpc": "2.0", "id": id.clone(), "result": { "chunk": chunk } });
+                        if !ws_write_text(stream, &frame.to_string()).await {
+                            return false;
+                        }
+                    } else {
+                        rx_open = false;
+                    }
+                }
+                outcome = &mut generate => break outcome,
+            }
+        };
+
+        // The future has returned, but chunks sent just before it finished may still
+        // be queued; forward whatever remains before the terminal frame.
+        while let Ok(chunk) = rx.try_recv() {
+            let frame = json!({ "jsonrpc": "2.0", "id": id.clone(), "result": { "chunk": chunk } });
+            if !ws_write_text(stream, &frame.to_string()).await {
+                return false;
+            }
+        }
+
+        match result {
+            Ok(full) => {
+                // Record the exchange once the full reply is known.
+                {
+                    let mut sessions_guard = dispatch.sessions.lock().unwrap();
+                    if let Some(session) = sessions_guard.get_mut(&session_id) {
+                        session.context.push(prompt.clone());
+                        session.context.push(full.clone());
+                        session.last_used = Instant::now();
+                    }
+                }
+                let done = json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": { "response": full, "turn": turn, "done": true }
+                });
+                ws_write_text(stream, &done.to_string()).await
+            }
             Err(e) => {
-                eprintln!("Failed to read from socket: {}", e);
+                let response = Self::error_response(id, -32603, &format!("Model error: {}", e));
+                ws_write_text(stream, &serde_json::to_string(&response).unwrap()).await
             }
         }
     }
 
+    /// Dispatch a raw JSON body, handling both single requests and batch arrays.
+    ///
+    /// Returns the serialized response body, or `None` when there is nothing to
+    /// send back — a lone notification, or a batch made up entirely of them.
+    async fn handle_message(
+        dispatch: &Dispatch,
+        body: &str,
+        auth: &Option<String>,
+    ) -> Option<String> {
+        let value: Value = match serde_json::from_str(body) {
+            Ok(value) => value,
+            Err(e) => {
+                // Parse error: the id cannot be recovered, so it is null.
+                let response = Self::error_response(Value::Null, -32700, &format!("Parse error: {}", e));
+                return Some(serde_json::to_string(&response).unwrap());
+            }
+        };
+
+        match value {
+            Value::Array(items) => {
+                let mut responses = Vec::new();
+                for item in items {
+                    if let Some(response) = Self::dispatch_value(dispatch, item, auth).await {
+                        responses.push(response);
+                    }
+                }
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(serde_json::to_string(&responses).unwrap())
+                }
+            }
+            single => Self::dispatch_value(dispatch, single, auth)
+                .await
+                .map(|response| serde_json::to_string(&response).unwrap()),
+        }
+    }
+
+    /// Turn one JSON-RPC element into a response, or `None` if it is a notification.
+    async fn dispatch_value(
+        dispatch: &Dispatch,
+        value: Value,
+        auth: &Option<String>,
+    ) -> Option<MCPResponse> {
+        let request: MCPRequest = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return Some(Self::error_response(Value::Null, -32700, &format!("Parse error: {}", e))),
+        };
+
+        // Requests without an id are notifications: run them, but emit no reply.
+        let is_notification = request.id.is_none();
+        let response = Self::process_request(request, dispatch, auth).await;
+        if is_notification {
+            None
+        } else {
+            Some(response)
+        }
+    }
+
     async fn process_request(
-        request: MCPRequest, 
-        models: &HashMap<String, ModelHandler>,
-        sessions: &Arc<Mutex<HashMap<String, Session>>>
+        request: MCPRequest,
+        dispatch: &Dispatch,
+        auth: &Option<String>,
     ) -> MCPResponse {
+        // Echo back the request id (null for parse/notification contexts).
+        let id = request.id.clone().unwrap_or(Value::Null);
+
+        // Reject unauthenticated callers before dispatching the method.
+        let caller = match Self::authenticate(&dispatch.tokens, auth, &request.params) {
+            Ok(caller) => caller,
+            Err(()) => return Self::error_response(id, -32001, "Unauthorized: invalid or missing token"),
+        };
+
         match request.method.as_str() {
             "session.create" => {
                 let model = match request.params.get("model") {
                     Some(Value::String(model)) => model.clone(),
-                    _ => return Self::error_response(request.id, -32602, "Invalid params: missing model")
+                    _ => return Self::error_response(id.clone(), -32602, "Invalid params: missing model")
                 };
-                
-                if !models.contains_key(&model) {
-                    return Self::error_response(request.id, -32602, &format!("Model not found: {}", model));
+
+                if !dispatch.models.contains_key(&model) {
+                    return Self::error_response(id.clone(), -32602, &format!("Model not found: {}", model));
                 }
-                
+
                 let session_id = uuid::Uuid::new_v4().to_string();
-                
-                let mut sessions_guard = sessions.lock().unwrap();
+
+                let mut sessions_guard = dispatch.sessions.lock().unwrap();
+                if sessions_guard.len() >= dispatch.max_sessions {
+                    return Self::error_response(id.clone(), -32000, "Too many sessions");
+                }
+                let now = Instant::now();
                 sessions_guard.insert(session_id.clone(), Session {
                     model,
                     context: Vec::new(),
+                    owner: caller.clone(),
+                    created: now,
+                    last_used: now,
                 });
-                
+
                 MCPResponse {
-                    id: request.id,
+                    jsonrpc: default_jsonrpc(),
+                    id: id.clone(),
                     result: json!({ "session_id": session_id }),
                     error: None,
                 }
@@ -152,72 +748,113 @@ impl MCPServer {
             "session.generate" => {
                 let session_id = match request.params.get("session_id") {
                     Some(Value::String(sid)) => sid.clone(),
-                    _ => return Self::error_response(request.id, -32602, "Invalid params: missing session_id")
+                    _ => return Self::error_response(id.clone(), -32602, "Invalid params: missing session_id")
                 };
                 
                 let prompt = match request.params.get("prompt") {
-                    Some(Value::String(p)) => p,
-                    _ => return Self::error_response(request.id, -32602, "Invalid params: missing prompt")
+                    Some(Value::String(p)) => p.clone(),
+                    _ => return Self::error_response(id.clone(), -32602, "Invalid params: missing prompt")
                 };
-                
-                let mut sessions_guard = sessions.lock().unwrap();
-                let session = match sessions_guard.get_mut(&session_id) {
-                    Some(s) => s,
-                    None => return Self::error_response(request.id, -32602, &format!("Session not found: {}", session_id))
-                };
-                
-                let model_handler = match models.get(&session.model) {
-                    Some(handler) => handler,
-                    None => return Self::error_response(request.id, -32603, "Internal error: model handler not found")
+
+                // Snapshot the model and context, releasing the lock before awaiting.
+                let (model, context) = {
+                    let sessions_guard = dispatch.sessions.lock().unwrap();
+                    let session = match sessions_guard.get(&session_id) {
+                        Some(session) => session,
+                        None => return Self::error_response(id.clone(), -32602, &format!("Session not found: {}", session_id))
+                    };
+                    if session.owner != caller {
+                        return Self::error_response(id.clone(), -32001, "Unauthorized: session owned by another token");
+                    }
+                    match dispatch.models.get(&session.model) {
+                        Some(model) => (model.clone(), session.context.clone()),
+                        None => return Self::error_response(id.clone(), -32603, "Internal error: model handler not found")
+                    }
                 };
-                
-                match model_handler(&prompt, &session.context) {
+
+                // Non-streaming HTTP/stdio clients only want the aggregate, so the
+                // chunk receiver is dropped and per-chunk sends are discarded.
+                let (tx, _rx) = mpsc::unbounded_channel();
+                match model.generate(&prompt, &context, tx).await {
                     Ok(response) => {
-                        // Add to context
-                        session.context.push(prompt.clone());
-                        session.context.push(response.clone());
-                        
+                        // Record the exchange in the session's context.
+                        let mut sessions_guard = dispatch.sessions.lock().unwrap();
+                        if let Some(session) = sessions_guard.get_mut(&session_id) {
+                            session.context.push(prompt);
+                            session.context.push(response.clone());
+                            session.last_used = Instant::now();
+                        }
+
                         MCPResponse {
-                            id: request.id,
+                            jsonrpc: default_jsonrpc(),
+                            id: id.clone(),
                             result: json!({ "response": response }),
                             error: None,
                         }
                     },
-                    Err(e) => Self::error_response(request.id, -32603, &format!("Model error: {}", e))
+                    Err(e) => Self::error_response(id.clone(), -32603, &format!("Model error: {}", e))
                 }
             },
             "session.close" => {
                 let session_id = match request.params.get("session_id") {
                     Some(Value::String(sid)) => sid.clone(),
-                    _ => return Self::error_response(request.id, -32602, "Invalid params: missing session_id")
+                    _ => return Self::error_response(id.clone(), -32602, "Invalid params: missing session_id")
                 };
                 
-                let mut sessions_guard = sessions.lock().unwrap();
-                if sessions_guard.remove(&session_id).is_none() {
-                    return Self::error_response(request.id, -32602, &format!("Session not found: {}", session_id));
+                let mut sessions_guard = dispatch.sessions.lock().unwrap();
+                match sessions_guard.get(&session_id) {
+                    Some(session) if session.owner != caller => {
+                        return Self::error_response(id.clone(), -32001, "Unauthorized: session owned by another token");
+                    }
+                    Some(_) => { sessions_guard.remove(&session_id); }
+                    None => return Self::error_response(id.clone(), -32602, &format!("Session not found: {}", session_id))
                 }
-                
+
                 MCPResponse {
-                    id: request.id,
+                    jsonrpc: default_jsonrpc(),
+                    id: id.clone(),
                     result: json!({"success": true}),
                     error: None,
                 }
             },
+            "session.list" => {
+                // List the caller's own sessions with model, turn count, and idle age.
+                let sessions_guard = dispatch.sessions.lock().unwrap();
+                let sessions_info: Vec<Value> = sessions_guard.iter()
+                    .filter(|(_, session)| session.owner == caller)
+                    .map(|(session_id, session)| json!({
+                        "session_id": session_id,
+                        "model": session.model,
+                        "turns": session.context.len() / 2,
+                        "age_secs": session.created.elapsed().as_secs(),
+                        "idle_secs": session.last_used.elapsed().as_secs(),
+                    }))
+                    .collect();
+
+                MCPResponse {
+                    jsonrpc: default_jsonrpc(),
+                    id: id.clone(),
+                    result: json!({ "sessions": sessions_info }),
+                    error: None,
+                }
+            },
             "models.list" => {
-                let model_names: Vec<String> = models.keys().cloned().collect();
+                let model_names: Vec<String> = dispatch.models.keys().cloned().collect();
                 
                 MCPResponse {
-                    id: request.id,
+                    jsonrpc: default_jsonrpc(),
+                    id: id.clone(),
                     result: json!({"models": model_names}),
                     error: None,
                 }
             },
-            _ => Self::error_response(request.id, -32601, &format!("Method not found: {}", request.method))
+            _ => Self::error_response(id.clone(), -32601, &format!("Method not found: {}", request.method))
         }
     }
     
-    fn error_response(id: String, code: i32, message: &str) -> MCPResponse {
+    fn error_response(id: Value, code: i32, message: &str) -> MCPResponse {
         MCPResponse {
+            jsonrpc: default_jsonrpc(),
             id,
             result: Value::Null,
             error: Some(MCPError {
@@ -228,36 +865,196 @@ impl MCPServer {
     }
 }
 
-// Example model handler
-fn example_model_handler(prompt: &str, context: &[String]) -> Result<String, Box<dyn Error + Send + Sync>> {
-    let context_len = context.len() / 2;
-    Ok(format!("Response to: {}. This is turn #{} in the conversation.", prompt, context_len + 1))
+// Example model backend
+struct ExampleModel;
+
+#[async_trait::async_trait]
+impl Model for ExampleModel {
+    async fn generate(
+        &self,
+        prompt: &str,
+        context: &[String],
+        tx: mpsc::UnboundedSender<String>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let context_len = context.len() / 2;
+        let full = format!("Response to: {}. This is turn #{} in the conversation.", prompt, context_len + 1);
+        // Yield the reply word by word as it is produced. A real backend would
+        // await between sends; streaming clients receive each chunk immediately.
+        for chunk in full.split_inclusive(' ') {
+            let _ = tx.send(chunk.to_string());
+        }
+        Ok(full)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // Set up the server with registered models
+    let mut server = MCPServer::new();
+    server.register_model("example-model", Arc::new(ExampleModel));
+
+    // Load the accepted bearer tokens from MCP_TOKENS (comma-separated). An empty
+    // set leaves the server open.
+    server.tokens = Arc::new(
+        std::env::var("MCP_TOKENS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect(),
+    );
+
+    // Session capacity and idle expiry are configurable via the environment.
+    if let Ok(cap) = std::env::var("MCP_MAX_SESSIONS") {
+        if let Ok(cap) = cap.parse() {
+            server.max_sessions = cap;
+        }
+    }
+    if let Ok(ttl) = std::env::var("MCP_SESSION_TTL_SECS") {
+        if let Ok(ttl) = ttl.parse() {
+            server.idle_ttl = Duration::from_secs(ttl);
+        }
+    }
+    if let Ok(size) = std::env::var("MCP_MAX_BODY_SIZE") {
+        if let Ok(size) = size.parse() {
+            server.max_body_size = size;
+        }
+    }
+
+    // Sweep idle sessions in the background so crashed clients don't leak memory.
+    MCPServer::spawn_reaper(server.sessions.clone(), server.idle_ttl);
+
+    let dispatch = server.dispatch();
+
+    // Pick the transport: stdio when requested, otherwise the TCP/HTTP listener.
+    let use_stdio = std::env::args().any(|arg| arg == "--stdio")
+        || std::env::var("MCP_TRANSPORT").map(|t| t == "stdio").unwrap_or(false);
+    if use_stdio {
+        MCPServer::run_stdio(dispatch).await;
+        return Ok(());
+    }
+
     let addr = "127.0.0.1:8080";
     let listener = TcpListener::bind(addr).await?;
     println!("HTTP MCP Server listening on {}", addr);
-    
-    // Set up the server with registered models
-    let mut server = MCPServer::new();
-    server.register_model("example-model", example_model_handler);
-    
-    let models = server.models.clone();
-    let sessions = server.sessions.clone();
-    
+
     // Accept connections
     while let Ok((stream, addr)) = listener.accept().await {
         println!("New client connected: {}", addr);
-        
-        let client_models = models.clone();
-        let client_sessions = sessions.clone();
-        
+
+        let dispatch = dispatch.clone();
         tokio::spawn(async move {
-            MCPServer::handle_client(stream, client_models, client_sessions).await;
+            MCPServer::handle_client(stream, dispatch).await;
         });
     }
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server with the example model registered and default limits.
+    fn test_dispatch() -> Dispatch {
+        let mut server = MCPServer::new();
+        server.register_model("example-model", Arc::new(ExampleModel));
+        server.dispatch()
+    }
+
+    /// Build a masked text frame the way a browser client would send one.
+    fn masked_text_frame(text: &str) -> Vec<u8> {
+        let payload = text.as_bytes();
+        let mask = [0x12u8, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81u8]; // FIN + text opcode
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// A connected pair of loopback sockets for exercising the frame helpers.
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn ws_write_text_round_trips_through_ws_read() {
+        let (mut a, mut b) = socket_pair().await;
+        assert!(ws_write_text(&mut a, "hello world").await);
+        let mut buffer = Vec::new();
+        match ws_read(&mut b, &mut buffer, 1 << 20).await {
+            WsFrame::Text(text) => assert_eq!(text, "hello world"),
+            _ => panic!("expected a text frame"),
+        }
+    }
+
+    #[tokio::test]
+    async fn ws_read_decodes_masked_client_frame() {
+        let (mut a, mut b) = socket_pair().await;
+        b.write_all(&masked_text_frame("ping")).await.unwrap();
+        let mut buffer = Vec::new();
+        match ws_read(&mut a, &mut buffer, 1 << 20).await {
+            WsFrame::Text(text) => assert_eq!(text, "ping"),
+            _ => panic!("expected a text frame"),
+        }
+    }
+
+    #[tokio::test]
+    async fn ws_read_rejects_oversized_frame() {
+        let (mut a, mut b) = socket_pair().await;
+        b.write_all(&masked_text_frame("too large")).await.unwrap();
+        let mut buffer = Vec::new();
+        assert!(matches!(ws_read(&mut a, &mut buffer, 4).await, WsFrame::Close));
+    }
+
+    #[tokio::test]
+    async fn notification_produces_no_reply() {
+        let dispatch = test_dispatch();
+        let body = r#"{"jsonrpc":"2.0","method":"models.list"}"#;
+        assert!(MCPServer::handle_message(&dispatch, body, &None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_omits_notification_responses() {
+        let dispatch = test_dispatch();
+        let body = r#"[{"jsonrpc":"2.0","method":"models.list"},{"jsonrpc":"2.0","id":1,"method":"models.list"}]"#;
+        let out = MCPServer::handle_message(&dispatch, body, &None).await.unwrap();
+        let parsed: Value = serde_json::from_str(&out).unwrap();
+        let responses = parsed.as_array().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["id"], json!(1));
+    }
+
+    #[tokio::test]
+    async fn session_generate_rejects_other_owner() {
+        let dispatch = test_dispatch();
+
+        // Without configured tokens the caller identity comes from the bearer
+        // value, so Alice and Bob are distinct owners.
+        let create: MCPRequest = serde_json::from_str(
+            r#"{"jsonrpc":"2.0","id":1,"method":"session.create","params":{"model":"example-model"}}"#,
+        )
+        .unwrap();
+        let created = MCPServer::process_request(create, &dispatch, &Some("alice".to_string())).await;
+        let session_id = created.result["session_id"].as_str().unwrap().to_string();
+
+        let generate: MCPRequest = serde_json::from_str(&format!(
+            r#"{{"jsonrpc":"2.0","id":2,"method":"session.generate","params":{{"session_id":"{}","prompt":"hi"}}}}"#,
+            session_id
+        ))
+        .unwrap();
+        let denied = MCPServer::process_request(generate, &dispatch, &Some("bob".to_string())).await;
+        assert_eq!(denied.error.unwrap().code, -32001);
+    }
+}